@@ -0,0 +1,56 @@
+use clap::Parser;
+
+/// Default size of the OS-level read buffer, i.e. how much data is pulled
+/// off disk per syscall. Chosen empirically (see the `#[ignore]`d sweep
+/// benchmarks in `main.rs`), the same way inferno settles on
+/// `CAPACITY_READER`.
+pub const CAPACITY_READER: usize = 128 * 1024;
+
+/// Default target size, in bytes, of the work unit handed to a single
+/// parsing task. This used to be `file_size / num_cpus^2`, which degrades
+/// at both ends (every file was cut into the same num_cpus^2 chunks, tiny
+/// ones for tiny files and huge ones for huge files); a flat, benchmarked
+/// constant keeps each job the same size and lets the number of jobs scale
+/// with file size instead. Mirrors inferno's `DEFAULT_NSTACKS_PER_JOB`.
+pub const DEFAULT_BYTES_PER_JOB: usize = 16 * 1024 * 1024;
+
+/// Default extra slack, in bytes, allocated on top of `bytes_per_job` for
+/// each chunk buffer, so the remainder carried over from the previous
+/// chunk (kept, to realign reads on newline boundaries) still fits without
+/// growing the buffer mid-chunk.
+pub const DEFAULT_CHUNK_SLACK: usize = 1024 * 10;
+
+/// CLI options for the measurements parser. The OS read-buffer size and
+/// the per-task work-unit size are deliberately separate knobs: the former
+/// controls how much is pulled off disk per syscall, the latter controls
+/// how much work one parsing task gets.
+#[derive(Parser, Debug)]
+#[command(about = "Parses a semicolon-delimited measurements file and reports per-city low/mean/high temperatures.")]
+pub struct Config {
+    /// Path to the measurements file to parse.
+    #[arg(default_value = "measurements.txt")]
+    pub input_path: String,
+
+    /// Size, in bytes, of the OS-level read buffer.
+    #[arg(long, default_value_t = CAPACITY_READER)]
+    pub read_buffer_size: usize,
+
+    /// Target size, in bytes, of the work unit handed to each parsing task.
+    #[arg(long, default_value_t = DEFAULT_BYTES_PER_JOB)]
+    pub bytes_per_job: usize,
+
+    /// Extra slack, in bytes, allocated on top of `bytes_per_job` per chunk
+    /// buffer for the newline-realignment remainder.
+    #[arg(long, default_value_t = DEFAULT_CHUNK_SLACK)]
+    pub chunk_slack: usize,
+
+    /// Maximum number of chunks that may be queued or mid-parse at once.
+    /// This is the in-flight backpressure window, not the number of
+    /// parsing tasks: one task is spawned per chunk regardless, and how
+    /// many run concurrently is up to async-std's own thread pool (sized
+    /// off the number of logical CPUs). This flag only bounds how far
+    /// ahead of the workers the reader is allowed to get, and therefore
+    /// peak memory. Defaults to twice the number of logical CPUs.
+    #[arg(long)]
+    pub max_chunks_in_flight: Option<usize>,
+}
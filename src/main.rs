@@ -1,6 +1,22 @@
 use async_std::task;
-use std::{fs::File, time::Instant, io::{BufReader, Read, self}, collections::HashMap};
-use crossbeam::{channel::{self, Sender}, select};
+use std::{fs::File, time::Instant, io::{BufReader, Read, self}, sync::Arc};
+use ahash::RandomState;
+use clap::Parser;
+use crossbeam::channel::{self, Receiver, Sender};
+use dashmap::DashMap;
+use memchr::{memchr, memchr_iter};
+
+mod config;
+use config::Config;
+
+mod pool;
+use pool::{Pool, PooledBuffer};
+
+/// Keyed by city name, shared by every parsing task so results land
+/// directly in the aggregate instead of being merged on the main thread.
+/// `ahash` is used as the hasher since city names are short strings and
+/// the default SipHash dominates at this call volume.
+type CityMap = Arc<DashMap<String, CityMetrics, RandomState>>;
 
 #[derive(Clone, Debug)]
 struct CityMetrics {
@@ -11,80 +27,149 @@ struct CityMetrics {
     pub num_temps: u32,
 }
 
-async fn async_tally(sender: Sender<HashMap<String, CityMetrics>>, data: Vec<u8>) {
-    let mut cities:HashMap<String, CityMetrics> = HashMap::new();
+async fn async_tally(cities: CityMap, data: PooledBuffer) {
+    // memchr_iter walks the whole buffer with SIMD in one pass for line
+    // boundaries, rather than the byte-at-a-time `iter().enumerate()` scan
+    // this used to do. The delimiter is then found only within that one
+    // line's subslice instead of re-scanning via `split_once`.
     let mut start: usize = 0;
-    for (newline_index, &byte) in data.iter().enumerate() {
-        // line in read_lines() is soooooooo slooooooow. Resort to byte checking.
-        if byte == b'\n' {
-            if let Ok(line) = std::str::from_utf8(&data[start..newline_index]) {
-                let (city_name, temp_str) = line.split_once(';').expect(format!("Could not find delimeter in line '{}'", line).as_str());
-                let temperature = temp_str.parse::<f32>().expect(format!("Could not parse '{}' as f32", temp_str).as_str());
-                cities.entry(city_name.to_string()).and_modify(|city_metrics|{
-                    if temperature > city_metrics.high {
-                        city_metrics.high = temperature;
-                    } else if temperature < city_metrics.low {
-                        city_metrics.low = temperature;
-                    }
-                    city_metrics.num_temps += 1;
-                    city_metrics.temperature_sum += temperature;
-                }).or_insert(
-                    CityMetrics{
-                        high: temperature,
-                        low: temperature,
-                        mean: 0.0,
-                        temperature_sum: 0.0,
-                        num_temps: 1,
-                    }
-                );
-            }
-            start = newline_index + 1;
+    for newline_index in memchr_iter(b'\n', &data) {
+        let line = &data[start..newline_index];
+        start = newline_index + 1;
+
+        let Some(delimiter_index) = memchr(b';', line) else {
+            continue;
+        };
+        let city_name = match std::str::from_utf8(&line[..delimiter_index]) {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        let temp_str = match std::str::from_utf8(&line[delimiter_index + 1..]) {
+            Ok(s) => s,
+            Err(_) => continue,
+        };
+        let Ok(temperature) = temp_str.parse::<f32>() else {
+            continue;
+        };
+
+        // Per-entry locking: DashMap only holds the lock of the shard this
+        // city hashes to for the duration of the update, so concurrent
+        // tasks updating cities in different shards don't contend. Look
+        // the city up by borrowed &str first so an already-seen city
+        // (the overwhelming majority of rows) doesn't pay for a
+        // `to_string` allocation; only a never-before-seen city needs an
+        // owned key for the insert. The miss path still goes through
+        // `entry().and_modify().or_insert()` under one lock, so a city
+        // that gets inserted by a racing task between our `get_mut` miss
+        // and the `entry` call has this reading folded in via
+        // `and_modify` instead of silently dropped.
+        if let Some(mut city_metrics) = cities.get_mut(city_name) {
+            apply_temperature(&mut city_metrics, temperature);
+        } else {
+            cities.entry(city_name.to_string())
+                .and_modify(|city_metrics| apply_temperature(city_metrics, temperature))
+                .or_insert(CityMetrics{
+                    high: temperature,
+                    low: temperature,
+                    mean: 0.0,
+                    temperature_sum: temperature,
+                    num_temps: 1,
+                });
         }
     }
+}
 
-    sender.send(cities).expect("Failed to send a processed map of cities");
+fn apply_temperature(city_metrics: &mut CityMetrics, temperature: f32) {
+    if temperature > city_metrics.high {
+        city_metrics.high = temperature;
+    } else if temperature < city_metrics.low {
+        city_metrics.low = temperature;
+    }
+    city_metrics.num_temps += 1;
+    city_metrics.temperature_sum += temperature;
 }
 
-fn main() {    
-    // Setup and print
-    let file_path = "measurements.txt";
-    let num_processors = num_cpus::get();
-    let (sender, receiver) = channel::unbounded::<HashMap<String, CityMetrics>>();
-    let mut num_tasks = 0;
+/// Bundles `reader_stage`'s parameters so the function takes one argument
+/// instead of nine.
+struct ReaderStageConfig {
+    file_path: String,
+    read_buffer_size: usize,
+    pool: Pool,
+    cities: CityMap,
+    permit_tx: Sender<()>,
+    permit_rx: Receiver<()>,
+    chunk_size: usize,
+    extra_chunky: usize,
+    file_size: usize,
+}
 
-    println!("Number of processors: {}", num_processors);
-    println!("Reading in the {} file", file_path);
-    let file_metadata = std::fs::metadata(file_path).expect(format!("Cannot access meta data in file {}, file_path", file_path).as_str());
-    let file = File::open(file_path).expect("Could not read file");
-    let mut reader = BufReader::new(file);
-    let file_size: usize = file_metadata.len() as usize;
-    let mut file_bytes_read: usize = 0;
-    println!("File Size: {}", file_size);
+/// Owns the file and drives the read/re-align/dispatch loop that used to
+/// live inline in `main`. Runs as its own async task so its blocking reads
+/// (performed on a `spawn_blocking` thread) overlap with worker tasks
+/// parsing chunks handed off by earlier iterations, instead of the read
+/// loop and parsing serializing on one thread.
+async fn reader_stage(config: ReaderStageConfig) -> Vec<task::JoinHandle<()>> {
+    let ReaderStageConfig {
+        file_path,
+        read_buffer_size,
+        pool,
+        cities,
+        permit_tx,
+        permit_rx,
+        chunk_size,
+        extra_chunky,
+        file_size,
+    } = config;
 
-    // Smaller chunks make it faster to process, square/scale it to the num of processors.
-    let num_chunks = num_processors * num_processors;
-    let chunk_size = (file_size as usize / num_chunks) + 1;
-    let extra_chunky = 1024 * 10; // Allocate an extra 10 KB for each vector
-    println!("Splitting the vector into equal sized chunks of: {}", chunk_size);
-
-    let mut chunk_remainder = Vec::new();
+    let file = File::open(&file_path).expect("Could not read file");
+    let mut reader = BufReader::with_capacity(read_buffer_size, file);
+    let mut file_bytes_read: usize = 0;
+    let mut chunk_remainder: Vec<u8> = Vec::new();
+    let mut task_handles = Vec::new();
 
-    // Start execution
-    let app_start_time = Instant::now();
     loop {
+        permit_rx.recv().expect("In-flight permit channel closed unexpectedly");
+
         // Chunking explained:
         // Read in the data as fast as possible, easiest method is as bytes.
         // Once it has been read in, the data is likely misaligned.
         // Re-align the data by truncating the current chunk
         // and pre-pending it to the next chunk. Avoid data copies
         // and REALLY avoid vec bumping.
-        let mut chunk: Vec<u8> = vec![0; chunk_size + extra_chunky];
+        // The underlying Vec<u8> is checked out of `pool` and returned to it
+        // once `async_tally` finishes with it, so peak allocation stays
+        // bounded to roughly `max_chunks_in_flight` buffers instead of growing with
+        // the number of reads.
+        let mut chunk: PooledBuffer = pool.acquire(chunk_size + extra_chunky);
         let offset = chunk_remainder.len();
-        if chunk_remainder.len() > 0 {
+        if !chunk_remainder.is_empty() {
             chunk[..chunk_remainder.len()].copy_from_slice(&chunk_remainder);
             chunk_remainder.clear();
         }
-        let bytes_read = reader.read(&mut chunk[offset..]);
+
+        // The blocking std::fs::File::read calls run on a dedicated
+        // spawn_blocking thread, so the executor is not blocked on disk I/O
+        // and worker tasks keep parsing earlier chunks while this read is
+        // in flight. The chunk is filled one `read_buffer_size` slice at a
+        // time, so the size of each OS read is independent of the chunk size.
+        let (returned_reader, mut chunk, bytes_read) = task::spawn_blocking(move || {
+            let mut filled = offset;
+            let mut result: io::Result<()> = Ok(());
+            while filled < chunk.len() {
+                let end = chunk.len().min(filled + read_buffer_size.max(1));
+                match reader.read(&mut chunk[filled..end]) {
+                    Ok(0) => break,
+                    Ok(n) => filled += n,
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            }
+            (reader, chunk, result.map(|_| filled - offset))
+        }).await;
+        reader = returned_reader;
+
         match bytes_read {
             Ok(num_bytes) => {
                 if num_bytes == 0 {
@@ -93,6 +178,14 @@ fn main() {
                 file_bytes_read += num_bytes;
                 println!("Read file {} / {} bytes", file_bytes_read, file_size);
 
+                // The pool hands back recycled buffers verbatim, without
+                // re-zeroing bytes the previous occupant left behind, so
+                // `chunk` must be cut down to exactly what this read (plus
+                // the prepended remainder) actually produced before
+                // anything scans or parses it - otherwise the rev-scan and
+                // `async_tally` walk into stale bytes from a prior chunk.
+                chunk.truncate(offset + num_bytes);
+
                 if file_bytes_read < file_size {
                     if let Some(index) = chunk.iter().rev().position(|&x| x == b'\n') {
                         // The index is found, considering it's reversed, convert it to the original index
@@ -104,12 +197,19 @@ fn main() {
                     }
                 }
 
-                let sender_clone = sender.clone();
+                let cities_clone = cities.clone();
+                let permit_tx_clone = permit_tx.clone();
                 // ♥ async-std. Please, please, please keep this project going.
-                let _ = task::spawn(async move {
-                    async_tally(sender_clone, chunk).await
-                });
-                num_tasks += 1;
+                task_handles.push(task::spawn(async move {
+                    async_tally(cities_clone, chunk).await;
+                    // `permit_rx` is owned by `reader_stage` and dropped
+                    // when it returns after the last read, which can be
+                    // before every in-flight task has finished, so a send
+                    // here failing because of that drop is
+                    // expected and not fatal - the channel has already
+                    // done its job of gating how many chunks were read.
+                    let _ = permit_tx_clone.send(());
+                }));
             }
             Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
                 // EOF, unexpected but tolerable
@@ -117,49 +217,86 @@ fn main() {
             }
             Err(e) => {
                 // Some other error, dunno
-                println!("Failed to read file: {}", e.to_string());
+                println!("Failed to read file: {}", e);
                 break;
             }
         }
     }
+
+    task_handles
+}
+
+fn main() {
+    // Setup and print
+    let config = Config::parse();
+    let file_path = config.input_path.as_str();
+    let all_cities: CityMap = Arc::new(DashMap::with_hasher(RandomState::new()));
+
+    println!("Reading in the {} file", file_path);
+    let file_metadata = std::fs::metadata(file_path)
+        .unwrap_or_else(|e| panic!("Cannot access meta data in file {}: {}", file_path, e));
+    let file_size: usize = file_metadata.len() as usize;
+    println!("File Size: {}", file_size);
+
+    // Work-unit size is now a flat, benchmarked bytes-per-job target
+    // rather than file_size / num_cpus^2, which degraded on very large or
+    // very small files.
+    let chunk_size = config.bytes_per_job;
+    let extra_chunky = config.chunk_slack;
+    println!("Splitting the vector into equal sized chunks of: {}", chunk_size);
+
+    let pool = Pool::new();
+
+    // Gate the number of chunks in flight (queued or being parsed) so a
+    // multi-gigabyte file can't sit almost entirely resident as unparsed
+    // chunks before any task drains. `permit_tx` starts pre-loaded with
+    // `max_chunks_in_flight` tokens; the reader takes one before every
+    // read and a task hands its token back when it finishes, so once the
+    // ceiling is hit the reader blocks on the next `recv` until a task
+    // completes. This bounds how far the reader gets ahead of the
+    // workers - it does not bound how many workers run at once, which is
+    // up to async-std's own thread pool.
+    let max_chunks_in_flight = config.max_chunks_in_flight.unwrap_or_else(|| 2 * num_cpus::get());
+    let (permit_tx, permit_rx) = channel::bounded::<()>(max_chunks_in_flight);
+    for _ in 0..max_chunks_in_flight {
+        permit_tx.send(()).expect("Failed to pre-load in-flight permits");
+    }
+
+    // Start execution. The reader stage runs concurrently with the worker
+    // tasks it spawns, so `file_read_total_time` measures the read/dispatch
+    // stage on its own instead of the whole pipeline.
+    let app_start_time = Instant::now();
+    let task_handles = task::block_on(reader_stage(ReaderStageConfig {
+        file_path: file_path.to_string(),
+        read_buffer_size: config.read_buffer_size,
+        pool,
+        cities: all_cities.clone(),
+        permit_tx,
+        permit_rx,
+        chunk_size,
+        extra_chunky,
+        file_size,
+    }));
     let file_end_time  = Instant::now();
     let file_read_total_time = file_end_time - app_start_time;
     println!("Time spent reading in the file: {} ms", file_read_total_time.as_millis());
-    
-    let mut all_cities: HashMap<String, CityMetrics> = HashMap::new();
-    let mut count_tasks_completed = 0;
-    let total_tasks = num_tasks;
-    loop {
-        select! {
-            recv(receiver) -> msg => {
-                let cities = msg.expect("Main thread could not process message from child thread");
-                count_tasks_completed += 1;
-                println!("Tasks {} / {}, received a total of {} cities to compute", count_tasks_completed, total_tasks, cities.len());
-                
-                cities.into_iter().for_each(|(name, city)|{
-                    all_cities.entry(name).and_modify(|city_tally| {
-                        city_tally.num_temps += city.num_temps;
-                        city_tally.temperature_sum += city.temperature_sum;
-                        
-                        if city.high > city_tally.high {
-                            city_tally.high = city.high;
-                        }
-                        if city.low < city_tally.low {
-                            city_tally.low = city.low;
-                        }
-                    }).or_insert(city.clone());
-                });
 
-                num_tasks -= 1;
-                if num_tasks == 0 {
-                    break;
-                }
-            },
+    // Every task has already folded its results directly into `all_cities`,
+    // so there's no per-task map to merge here - just wait for any
+    // in-flight tasks that hadn't finished parsing by the time the reader
+    // stage read the last chunk.
+    let aggregation_start_time = Instant::now();
+    let total_tasks = task_handles.len();
+    task::block_on(async {
+        for handle in task_handles {
+            handle.await;
         }
-    }
+    });
+    let aggregation_total_time = Instant::now() - aggregation_start_time;
+    println!("All {} tasks finished parsing", total_tasks);
 
     let mut total_num_temps = 0;
-    all_cities.iter_mut().for_each(|(name, metric)|{
+    all_cities.iter_mut().for_each(|mut metric|{
         metric.mean = metric.temperature_sum / metric.num_temps as f32;
         total_num_temps += metric.num_temps;
         println!(
@@ -167,15 +304,11 @@ fn main() {
         \tHigh Temp: {}
         \tLow Temp: {}
         \tMean Temp: {:.1}",
-        name, metric.high, metric.low, metric.mean);
+        metric.key(), metric.high, metric.low, metric.mean);
     });
 
     let app_end_time = Instant::now();
     let app_total_time = app_end_time - app_start_time;
-    // Processing time occurs inline with the file reading, however these
-    // operations interfere with each other. The numbers will vary
-    // depending on their interference.
-    let estimated_time_processing = app_total_time - file_read_total_time;
     println!(
        "=================================\n\
        {:<30}{:>11} \n\
@@ -184,10 +317,90 @@ fn main() {
        {:<27}{:>8} ms",
        "Total Temperatures Processed: ",
        total_num_temps,
-       "Time reading in file:",
+       "Time reading/dispatching file:",
        file_read_total_time.as_millis(),
-       "Estimated Time processing:",
-       estimated_time_processing.as_millis(),
+       "Tail aggregation time:",
+       aggregation_total_time.as_millis(),
        "Total time:",
        app_total_time.as_millis());
+}
+
+#[cfg(test)]
+mod benches {
+    use super::*;
+    use config::{CAPACITY_READER, DEFAULT_BYTES_PER_JOB, DEFAULT_CHUNK_SLACK};
+    use std::io::Write;
+
+    fn write_synthetic_measurements(path: &std::path::Path, num_lines: usize) {
+        let mut file = File::create(path).expect("Could not create synthetic measurements file");
+        for i in 0..num_lines {
+            writeln!(file, "City{};{:.1}", i % 500, (i % 999) as f32 / 10.0)
+                .expect("Could not write synthetic line");
+        }
+    }
+
+    fn run_pipeline(path: &std::path::Path, read_buffer_size: usize, bytes_per_job: usize) {
+        let file_size = std::fs::metadata(path).unwrap().len() as usize;
+        let all_cities: CityMap = Arc::new(DashMap::with_hasher(RandomState::new()));
+        let pool = Pool::new();
+        let max_chunks_in_flight = 2 * num_cpus::get();
+        let (permit_tx, permit_rx) = channel::bounded::<()>(max_chunks_in_flight);
+        for _ in 0..max_chunks_in_flight {
+            permit_tx.send(()).expect("Failed to pre-load in-flight permits");
+        }
+
+        task::block_on(async {
+            let task_handles = reader_stage(ReaderStageConfig {
+                file_path: path.to_str().expect("Synthetic path is not valid UTF-8").to_string(),
+                read_buffer_size,
+                pool,
+                cities: all_cities,
+                permit_tx,
+                permit_rx,
+                chunk_size: bytes_per_job,
+                extra_chunky: DEFAULT_CHUNK_SLACK,
+                file_size,
+            }).await;
+            for handle in task_handles {
+                handle.await;
+            }
+        });
+    }
+
+    /// Not a correctness check - sweeps `bytes_per_job` over a synthetic
+    /// measurements file and prints wall-clock time for each, the way
+    /// inferno's benchmarks justify `DEFAULT_NSTACKS_PER_JOB`. Run
+    /// explicitly with `cargo test --release -- --ignored bench_bytes_per_job_sweep`.
+    #[test]
+    #[ignore]
+    fn bench_bytes_per_job_sweep() {
+        let path = std::env::temp_dir().join("onebrc_bytes_per_job_bench.txt");
+        write_synthetic_measurements(&path, 2_000_000);
+
+        for bytes_per_job in [1024 * 1024, 4 * 1024 * 1024, DEFAULT_BYTES_PER_JOB, 64 * 1024 * 1024] {
+            let start = Instant::now();
+            run_pipeline(&path, CAPACITY_READER, bytes_per_job);
+            println!("bytes_per_job={} total_time={:?}", bytes_per_job, start.elapsed());
+        }
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    /// Sweeps the OS-level read-buffer size with `bytes_per_job` held at
+    /// the default, to justify `CAPACITY_READER` independently of the
+    /// per-task work-unit size.
+    #[test]
+    #[ignore]
+    fn bench_read_buffer_size_sweep() {
+        let path = std::env::temp_dir().join("onebrc_read_buffer_bench.txt");
+        write_synthetic_measurements(&path, 2_000_000);
+
+        for read_buffer_size in [8 * 1024, 32 * 1024, CAPACITY_READER, 512 * 1024] {
+            let start = Instant::now();
+            run_pipeline(&path, read_buffer_size, DEFAULT_BYTES_PER_JOB);
+            println!("read_buffer_size={} total_time={:?}", read_buffer_size, start.elapsed());
+        }
+
+        std::fs::remove_file(&path).ok();
+    }
 }
\ No newline at end of file
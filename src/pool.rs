@@ -0,0 +1,67 @@
+use std::sync::{Arc, Mutex};
+
+/// A simple pool of reusable byte buffers, modeled on `byte_pool::BytePool`.
+///
+/// Chunk processing in `reader_stage` otherwise allocates (and drops) a fresh
+/// `Vec<u8>` on every `reader.read`, which over a billion-row run means
+/// gigabytes of transient allocations. `Pool` lets the read loop recycle
+/// buffers once a task is done with them instead.
+#[derive(Clone)]
+pub struct Pool {
+    inner: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl Pool {
+    pub fn new() -> Self {
+        Pool {
+            inner: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Acquire a buffer with capacity for at least `min_len` bytes, resized
+    /// to exactly `min_len`. Reuses a recycled buffer if one big enough is
+    /// available, otherwise allocates a new one.
+    pub fn acquire(&self, min_len: usize) -> PooledBuffer {
+        let mut buffers = self.inner.lock().expect("byte pool mutex poisoned");
+        let position = buffers.iter().position(|buf| buf.capacity() >= min_len);
+        let mut buf = match position {
+            Some(index) => buffers.swap_remove(index),
+            None => Vec::with_capacity(min_len),
+        };
+        buf.resize(min_len, 0);
+
+        PooledBuffer {
+            buf: Some(buf),
+            pool: self.inner.clone(),
+        }
+    }
+}
+
+/// A `Vec<u8>` checked out of a [`Pool`]. On drop, the buffer is returned to
+/// the pool so the next `reader.read` can reuse it instead of allocating.
+pub struct PooledBuffer {
+    buf: Option<Vec<u8>>,
+    pool: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl std::ops::Deref for PooledBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        self.buf.as_ref().expect("buffer already taken")
+    }
+}
+
+impl std::ops::DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        self.buf.as_mut().expect("buffer already taken")
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.pool.lock().expect("byte pool mutex poisoned").push(buf);
+        }
+    }
+}